@@ -63,10 +63,18 @@
 //!
 //! # Threads
 //!
-//! Internally a thread gets spawned for each std stream it's listening to (stdout and stderr).
-//! Another thread is started, that is in a blocking wait until the child process is done.
-//! This means that mio-child-process uses between 1 to 3 threads for every process that gets started.
+//! On Linux, stdio streams are serviced by a small, fixed-size pool of background threads
+//! shared by every spawned process (see `stdio_pool`), instead of one thread per stream. Exit
+//! notification is likewise handled by a single global SIGCHLD-driven reaper thread shared by
+//! every spawned process (see `reaper`), rather than one blocking wait thread per process.
+//!
+//! On other platforms a dedicated thread is still started for each std stream it's listening
+//! to (stdout and stderr; a process spawned with `CommandAsync::spawn_pty` only has a single
+//! combined stream, so it only spawns one), plus one thread per process blocking in `wait()`
+//! until the child is done.
 
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate mio;
 extern crate mio_extras;
 #[cfg(target_os = "windows")]
@@ -76,28 +84,112 @@ use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio_extras::channel::{channel, Receiver, Sender};
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::spawn;
 
+#[cfg(target_os = "linux")]
+mod reaper;
+
+#[cfg(target_os = "linux")]
+mod stdio_pool;
+
+mod pty;
+
 #[cfg(test)]
 mod test;
 
+/// Overrides how many background threads service child stdio streams (see the `Threads`
+/// section above), for applications that can size this ahead of time better than the
+/// default.
+///
+/// Has no effect once the first stdio stream has already been registered, since the pool is
+/// created lazily on first use and reused for the rest of the process's life - call this
+/// before spawning anything if the default doesn't suit your workload.
+///
+/// This is a no-op on platforms other than Linux, where a dedicated thread is used per stream
+/// instead of a shared pool.
+#[cfg(target_os = "linux")]
+pub fn set_stdio_pool_size(size: usize) {
+    stdio_pool::set_pool_size(size);
+}
+
+/// Overrides how many background threads service child stdio streams; see the Linux-only
+/// version of this function for details. This platform uses a dedicated thread per stream
+/// instead of a shared pool, so this is a no-op.
+#[cfg(not(target_os = "linux"))]
+pub fn set_stdio_pool_size(_size: usize) {}
+
 /// Extension trait to implement an async spawner on the Command struct
 pub trait CommandAsync {
     /// Spawn an async child process
     fn spawn_async(&mut self) -> Result<Process>;
+
+    /// Spawn an async child process whose stdout/stderr are delivered as raw,
+    /// unmodified bytes instead of being interpreted as UTF8.
+    ///
+    /// Use this when the child produces binary output (images, compressed streams,
+    /// protobuf, ...) that would otherwise risk a `ProcessEvent::Utf8Error`.
+    fn spawn_async_raw(&mut self) -> Result<Process>;
+
+    /// Spawn an async child process whose stdout/stderr are delivered one complete line at
+    /// a time via `ProcessEvent::Line`, instead of arbitrary-sized `ProcessEvent::Data`
+    /// chunks that callers would otherwise have to reassemble themselves.
+    fn spawn_async_lines(&mut self) -> Result<Process>;
+
+    /// Spawn the child attached to a freshly allocated pseudo-terminal of `size` `(rows,
+    /// cols)`, instead of plain pipes, so interactive or terminal-detecting programs (shells,
+    /// REPLs, `ssh`, progress bars) behave as they would when run from a real terminal.
+    ///
+    /// Stdin, stdout and stderr are all connected to the same pty slave, so output arrives as
+    /// a single combined stream of `ProcessEvent::Data` on `StdioChannel::Stdout`. Use
+    /// `Process::resize` to propagate window-size changes to the child.
+    fn spawn_pty(&mut self, size: (u16, u16)) -> Result<Process>;
 }
 
 impl CommandAsync for Command {
     fn spawn_async(&mut self) -> Result<Process> {
         let child = self.spawn()?;
-        Ok(Process::from_child(child))
+        Ok(Process::from_child(child, ReaderMode::Utf8))
+    }
+
+    fn spawn_async_raw(&mut self) -> Result<Process> {
+        let child = self.spawn()?;
+        Ok(Process::from_child(child, ReaderMode::Raw))
     }
+
+    fn spawn_async_lines(&mut self) -> Result<Process> {
+        let child = self.spawn()?;
+        Ok(Process::from_child(child, ReaderMode::Line))
+    }
+
+    fn spawn_pty(&mut self, size: (u16, u16)) -> Result<Process> {
+        let pty = pty::spawn(self, size)?;
+        Ok(Process::from_pty(pty))
+    }
+}
+
+/// Controls how a stdio reader thread interprets the bytes it reads from a child process.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ReaderMode {
+    /// Buffer partial UTF8 sequences across reads and emit `ProcessEvent::Data`.
+    Utf8,
+    /// Forward bytes unmodified as `ProcessEvent::RawData`.
+    Raw,
+    /// Buffer partial UTF8 sequences and lines across reads and emit `ProcessEvent::Line`.
+    Line,
 }
 
 /// An async child process
 pub struct Process {
     receiver: Receiver<ProcessEvent>,
+    sender: Sender<ProcessEvent>,
     stdin: Option<std::process::ChildStdin>,
+    pty_master: Option<pty::Master>,
+    /// Set to `true` right before a `ProcessEvent::Exit` or `ProcessEvent::CommandError` is
+    /// sent, so `with_timeout`'s watcher can tell a real completion apart from the process's
+    /// `pid` merely looking alive (e.g. a reaped zombie, or a reused pid).
+    exited: Arc<AtomicBool>,
     id: u32,
 }
 
@@ -107,19 +199,128 @@ impl Process {
         self.receiver.try_recv()
     }
 
-    pub(crate) fn from_child(mut child: Child) -> Process {
+    pub(crate) fn from_child(mut child: Child, mode: ReaderMode) -> Process {
         let (sender, receiver) = channel();
         if let Some(stdout) = child.stdout.take() {
-            spawn(create_reader(stdout, sender.clone(), StdioChannel::Stdout));
+            Self::spawn_stdio_reader(stdout, sender.clone(), StdioChannel::Stdout, mode);
         }
         if let Some(stderr) = child.stderr.take() {
-            spawn(create_reader(stderr, sender.clone(), StdioChannel::Stderr));
+            Self::spawn_stdio_reader(stderr, sender.clone(), StdioChannel::Stderr, mode);
         }
         let stdin = child.stdin.take();
         let id = child.id();
+        let process_sender = sender.clone();
+        let exited = Arc::new(AtomicBool::new(false));
+        Self::watch_exit(child, sender, mode, exited.clone());
+        Process {
+            receiver,
+            sender: process_sender,
+            stdin,
+            pty_master: None,
+            exited,
+            id,
+        }
+    }
+
+    /// Builds a `Process` driven by a pseudo-terminal's master end instead of separate
+    /// stdin/stdout/stderr pipes; see `CommandAsync::spawn_pty`.
+    pub(crate) fn from_pty(pty: pty::Pty) -> Process {
+        let pty::Pty {
+            master,
+            child,
+            reader,
+        } = pty;
+        let (sender, receiver) = channel();
+
+        // Must be an independent open file description, not a `dup()` of `master`: the pool
+        // sets `O_NONBLOCK` on whatever it's given, and that flag lives on the open file
+        // description, so a `dup`'d fd would make writes through `master` non-blocking too.
+        // Platforms whose pty master can't be reopened this way (ConPTY's two-pipe model) hand
+        // us an already-independent reader instead.
+        let reader_end = match reader {
+            Some(reader) => reader,
+            None => pty::reopen_reader(&master)
+                .expect("pty master fd should always be reopenable via /proc/self/fd"),
+        };
+        Self::spawn_stdio_reader(
+            reader_end,
+            sender.clone(),
+            StdioChannel::Stdout,
+            ReaderMode::Utf8,
+        );
+
+        let id = child.id();
+        let process_sender = sender.clone();
+        let exited = Arc::new(AtomicBool::new(false));
+        Self::watch_pty_exit(child, sender, exited.clone());
+        Process {
+            receiver,
+            sender: process_sender,
+            stdin: None,
+            pty_master: Some(master),
+            exited,
+            id,
+        }
+    }
+
+    /// Starts reading `stream` in the background, forwarding decoded output through `sender`.
+    ///
+    /// On Linux this hands the stream off to the shared, bounded `stdio_pool` instead of
+    /// parking a dedicated thread for it.
+    #[cfg(target_os = "linux")]
+    fn spawn_stdio_reader<T: Read + std::os::unix::io::IntoRawFd>(
+        stream: T,
+        sender: Sender<ProcessEvent>,
+        channel: StdioChannel,
+        mode: ReaderMode,
+    ) {
+        use std::os::unix::io::FromRawFd;
+
+        let file = unsafe { std::fs::File::from_raw_fd(stream.into_raw_fd()) };
+        stdio_pool::register_stream(file, sender, channel, mode);
+    }
+
+    /// Starts reading `stream` in the background, forwarding decoded output through `sender`.
+    #[cfg(not(target_os = "linux"))]
+    fn spawn_stdio_reader<T: Read + 'static>(
+        stream: T,
+        sender: Sender<ProcessEvent>,
+        channel: StdioChannel,
+        mode: ReaderMode,
+    ) {
+        spawn(create_reader(stream, sender, channel, mode));
+    }
+
+    /// Starts watching for the child's exit, forwarding a `ProcessEvent::Exit` (or
+    /// `ProcessEvent::CommandError`) to `sender` once it's available, and marking `exited`
+    /// `true` right beforehand so `with_timeout`'s watcher can see a real completion.
+    ///
+    /// On Linux this hands the child off to the global SIGCHLD-driven reaper instead of
+    /// parking a dedicated thread for it.
+    #[cfg(target_os = "linux")]
+    fn watch_exit(
+        child: Child,
+        sender: Sender<ProcessEvent>,
+        _mode: ReaderMode,
+        exited: Arc<AtomicBool>,
+    ) {
+        reaper::register(child, sender, exited);
+    }
+
+    /// Starts watching for the child's exit, forwarding a `ProcessEvent::Exit` (or
+    /// `ProcessEvent::CommandError`) to `sender` once it's available, and marking `exited`
+    /// `true` right beforehand so `with_timeout`'s watcher can see a real completion.
+    #[cfg(not(target_os = "linux"))]
+    fn watch_exit(
+        child: Child,
+        sender: Sender<ProcessEvent>,
+        mode: ReaderMode,
+        exited: Arc<AtomicBool>,
+    ) {
         spawn(move || {
-            let result = match child.wait_with_output() {
+            let mut result = match child.wait_with_output() {
                 Err(e) => {
+                    exited.store(true, Ordering::SeqCst);
                     let _ = sender.send(ProcessEvent::CommandError(e));
                     return;
                 }
@@ -127,107 +328,89 @@ impl Process {
             };
             if !result.stdout.is_empty()
                 && SendResult::Abort
-                    == try_send_buffer(&result.stdout, StdioChannel::Stdout, &sender)
+                    == send_remaining_output(
+                        &mut result.stdout,
+                        StdioChannel::Stdout,
+                        &sender,
+                        mode,
+                    )
             {
                 return;
             }
             if !result.stderr.is_empty()
                 && SendResult::Abort
-                    == try_send_buffer(&result.stderr, StdioChannel::Stderr, &sender)
+                    == send_remaining_output(
+                        &mut result.stderr,
+                        StdioChannel::Stderr,
+                        &sender,
+                        mode,
+                    )
             {
                 return;
             }
+            exited.store(true, Ordering::SeqCst);
             let _ = sender.send(ProcessEvent::Exit(result.status));
         });
-        Process {
-            receiver,
-            stdin,
-            id,
-        }
     }
 
-    /// Kill the process child, and all it's children.
-    #[cfg(target_os = "windows")]
-    pub fn kill(&mut self) -> Result<()> {
-        use std::collections::HashMap;
-        use std::io::Error;
-        use std::mem;
-        use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
-        use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
-        use winapi::um::tlhelp32::{
-            CreateToolhelp32Snapshot, Process32First, Process32Next, PROCESSENTRY32,
-            TH32CS_SNAPPROCESS,
-        };
-        use winapi::um::winnt::PROCESS_TERMINATE;
-
-        // We first need to make a list of all processes and their parents
-        // Then we'll go through the processes and list their ids and parent ids
-        // then we'll look up the current pid, find all processes that have this pid as their parent, and kill those first
-        type ParentID = u32;
-        type ChildID = u32;
-        let mut processes = HashMap::<ParentID, Vec<ChildID>>::new();
-
-        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
-        if snapshot == INVALID_HANDLE_VALUE {
-            unsafe {
-                CloseHandle(snapshot);
-            }
-            return Err(Error::last_os_error());
-        }
-
-        let mut process_entry_32: PROCESSENTRY32 = unsafe { mem::zeroed() };
-        process_entry_32.dwSize = mem::size_of::<PROCESSENTRY32>() as u32;
-        if 0 == unsafe { Process32First(snapshot, &mut process_entry_32) } {
-            unsafe {
-                CloseHandle(snapshot);
-            }
-            return Err(Error::last_os_error());
-        }
-
-        // Push first entry
-        processes
-            .entry(process_entry_32.th32ParentProcessID)
-            .or_insert_with(Vec::new)
-            .push(process_entry_32.th32ProcessID);
-
-        while unsafe { Process32Next(snapshot, &mut process_entry_32) } != 0 {
-            // Push subsequent entries
-            processes
-                .entry(process_entry_32.th32ParentProcessID)
-                .or_insert_with(Vec::new)
-                .push(process_entry_32.th32ProcessID);
-        }
+    /// Starts watching for a pty-backed child's exit; see `watch_exit`.
+    ///
+    /// On Linux this is just `watch_exit` reusing the global reaper, but `PtyChild` isn't
+    /// always a plain `Child` (see `pty::PtyChild`), so pty-backed processes get their own
+    /// entry point instead of going through `watch_exit` directly.
+    #[cfg(target_os = "linux")]
+    fn watch_pty_exit(child: pty::PtyChild, sender: Sender<ProcessEvent>, exited: Arc<AtomicBool>) {
+        let pty::PtyChild::Std(child) = child;
+        Self::watch_exit(child, sender, ReaderMode::Utf8, exited);
+    }
 
-        unsafe {
-            CloseHandle(snapshot);
-        }
+    /// Starts watching for a pty-backed child's exit; see `watch_exit`.
+    ///
+    /// ConPTY's child can't be waited on through `std::process::Child::wait_with_output` the
+    /// way `watch_exit` does, since it was never one (see `pty::PtyChild`), so this waits on
+    /// its raw process handle instead. A pty-backed process only ever has one combined stream,
+    /// already drained by its own reader thread, so there's no leftover stdout/stderr to flush
+    /// the way `watch_exit` does for a plain `Child`.
+    #[cfg(target_os = "windows")]
+    fn watch_pty_exit(child: pty::PtyChild, sender: Sender<ProcessEvent>, exited: Arc<AtomicBool>) {
+        spawn(move || {
+            let result = pty::wait_for_exit(child);
+            exited.store(true, Ordering::SeqCst);
+            let event = match result {
+                Ok(status) => ProcessEvent::Exit(status),
+                Err(e) => ProcessEvent::CommandError(e),
+            };
+            let _ = sender.send(event);
+        });
+    }
 
-        // Kill all children, then kills the process with the given `pid`
-        fn kill_pid(pid: u32, processes: &HashMap<ParentID, Vec<ChildID>>) -> Result<()> {
-            if let Some(children) = processes.get(&pid) {
-                for child in children {
-                    kill_pid(*child, processes)?;
-                }
-            }
-            // open a handle to the given pid
-            let handle = unsafe { OpenProcess(PROCESS_TERMINATE, 0, pid) };
-            if handle.is_null() || 0 == unsafe { TerminateProcess(handle, 0) } {
-                // handle not terminated
-                Err(Error::last_os_error())
-            } else {
-                Ok(())
-            }
-        }
+    /// Starts watching for a pty-backed child's exit; see `watch_exit`.
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    fn watch_pty_exit(child: pty::PtyChild, sender: Sender<ProcessEvent>, exited: Arc<AtomicBool>) {
+        let pty::PtyChild::Std(child) = child;
+        Self::watch_exit(child, sender, ReaderMode::Utf8, exited);
+    }
 
-        kill_pid(self.id(), &processes)
+    /// Kill the process child, and all it's children.
+    #[cfg(target_os = "windows")]
+    pub fn kill(&mut self) -> Result<()> {
+        force_kill(self.id())
     }
 
     /// Kill the process child, and all it's children.
     #[cfg(target_os = "linux")]
     pub fn kill(&mut self) -> Result<()> {
-        extern crate libc;
+        force_kill(self.id())
+    }
 
-        let result = unsafe { libc::kill(self.id() as i32, libc::SIGKILL) };
+    /// Sends the given signal to the process, letting it react (and potentially clean up)
+    /// instead of being killed outright.
+    ///
+    /// This is the Unix equivalent of `kill -<sig> <pid>`; common values are
+    /// `libc::SIGTERM`, `libc::SIGINT` and `libc::SIGHUP`.
+    #[cfg(target_os = "linux")]
+    pub fn signal(&mut self, sig: i32) -> Result<()> {
+        let result = unsafe { libc::kill(self.id() as i32, sig) };
         if result == 0 {
             Ok(())
         } else {
@@ -235,29 +418,107 @@ impl Process {
         }
     }
 
+    /// Tries to shut the process down politely before forcefully killing it.
+    ///
+    /// Sends a polite termination request (`SIGTERM` on Linux, a console-control event on
+    /// Windows) and returns immediately once it's sent. A background watcher waits up to
+    /// `timeout` for the existing exit machinery to report a real completion and, if the
+    /// process is still alive once `timeout` elapses, tears it down with the same forceful
+    /// `kill` used elsewhere in this crate.
+    ///
+    /// This never reads from the process's event channel itself, so every `Data`/`Line`/
+    /// `RawData`/`Exit`/... event the process produces while winding down still arrives
+    /// through the caller's own `try_recv`/`Evented` loop exactly as it would otherwise.
+    pub fn terminate_gracefully(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.send_polite_signal()?;
+        spawn_graceful_kill_watcher(self.exited.clone(), self.id, timeout);
+        Ok(())
+    }
+
+    /// Asks the process to shut down politely, ahead of the forceful `kill` that
+    /// `terminate_gracefully` escalates to if this doesn't work in time.
+    #[cfg(target_os = "linux")]
+    fn send_polite_signal(&mut self) -> Result<()> {
+        self.signal(libc::SIGTERM)
+    }
+
+    /// Asks the process to shut down politely, ahead of the forceful `kill` that
+    /// `terminate_gracefully` escalates to if this doesn't work in time.
+    #[cfg(target_os = "windows")]
+    fn send_polite_signal(&mut self) -> Result<()> {
+        use winapi::um::wincon::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+
+        let result = unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, self.id()) };
+        if result == 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns the OS-assigned process identifier associated with this child.
     pub fn id(&self) -> u32 {
         self.id
     }
+
+    /// Arranges for this process to be torn down if it hasn't exited before `timeout`
+    /// elapses.
+    ///
+    /// If the deadline passes before a `ProcessEvent::Exit` has been observed, a
+    /// `ProcessEvent::TimedOut` is sent through the same channel as every other event. When
+    /// `kill_on_timeout` is `true`, the process is also killed (using the same forceful
+    /// `kill` used elsewhere in this crate) once the deadline passes. On Windows that kill
+    /// also takes down the process's children; on Linux only the process itself is sent
+    /// `SIGKILL`, and its children are not signalled.
+    pub fn with_timeout(self, timeout: std::time::Duration, kill_on_timeout: bool) -> Self {
+        spawn_timeout_watcher(
+            self.exited.clone(),
+            self.id,
+            self.sender.clone(),
+            timeout,
+            kill_on_timeout,
+        );
+        self
+    }
+
+    /// Propagates a terminal window-size change (rows, cols) to a pty-backed process, so
+    /// full-screen programs (editors, multiplexers) redraw at the new dimensions.
+    ///
+    /// Returns `ErrorKind::NotConnected` if this process wasn't spawned with
+    /// `CommandAsync::spawn_pty`.
+    pub fn resize(&mut self, size: (u16, u16)) -> Result<()> {
+        match self.pty_master.as_ref() {
+            Some(master) => pty::resize(master, size),
+            None => Err(Error::from(ErrorKind::NotConnected)),
+        }
+    }
 }
 
 impl Write for Process {
-    /// Write a buffer to the Stdin stream of this child process.
+    /// Write a buffer to the Stdin stream of this child process, or to the pty master if this
+    /// process was spawned with `CommandAsync::spawn_pty`.
     ///
-    /// If the child is not created with `.stdin(Stdio::piped())`,
-    /// This function will return an error `ErrorKind::NotConnected`.
+    /// If the child is not created with `.stdin(Stdio::piped())` and wasn't spawned with a
+    /// pty, this function will return an error `ErrorKind::NotConnected`.
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if let Some(ref mut master) = self.pty_master {
+            return master.write(buf);
+        }
         match self.stdin.as_mut() {
             Some(ref mut stdin) => stdin.write(buf),
             None => Err(Error::from(ErrorKind::NotConnected)),
         }
     }
 
-    /// Flushed the Stdin stream of this child process.
+    /// Flushed the Stdin stream of this child process, or the pty master if this process was
+    /// spawned with `CommandAsync::spawn_pty`.
     ///
-    /// If the child is not created with `.stdin(Stdio::piped())`,
-    /// This function will return an error `ErrorKind::NotConnected`.
+    /// If the child is not created with `.stdin(Stdio::piped())` and wasn't spawned with a
+    /// pty, this function will return an error `ErrorKind::NotConnected`.
     fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut master) = self.pty_master {
+            return master.flush();
+        }
         match self.stdin.as_mut() {
             Some(ref mut stdin) => stdin.flush(),
             None => Err(Error::from(ErrorKind::NotConnected)),
@@ -280,54 +541,319 @@ impl Evented for Process {
     }
 }
 
+/// Kill the process with the given `pid`, and all it's children.
+#[cfg(target_os = "windows")]
+fn force_kill(pid: u32) -> Result<()> {
+    use std::collections::HashMap;
+    use std::mem;
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+    use winapi::um::tlhelp32::{
+        CreateToolhelp32Snapshot, Process32First, Process32Next, PROCESSENTRY32, TH32CS_SNAPPROCESS,
+    };
+    use winapi::um::winnt::PROCESS_TERMINATE;
+
+    // We first need to make a list of all processes and their parents
+    // Then we'll go through the processes and list their ids and parent ids
+    // then we'll look up the current pid, find all processes that have this pid as their parent, and kill those first
+    type ParentID = u32;
+    type ChildID = u32;
+    let mut processes = HashMap::<ParentID, Vec<ChildID>>::new();
+
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        unsafe {
+            CloseHandle(snapshot);
+        }
+        return Err(Error::last_os_error());
+    }
+
+    let mut process_entry_32: PROCESSENTRY32 = unsafe { mem::zeroed() };
+    process_entry_32.dwSize = mem::size_of::<PROCESSENTRY32>() as u32;
+    if 0 == unsafe { Process32First(snapshot, &mut process_entry_32) } {
+        unsafe {
+            CloseHandle(snapshot);
+        }
+        return Err(Error::last_os_error());
+    }
+
+    // Push first entry
+    processes
+        .entry(process_entry_32.th32ParentProcessID)
+        .or_insert_with(Vec::new)
+        .push(process_entry_32.th32ProcessID);
+
+    while unsafe { Process32Next(snapshot, &mut process_entry_32) } != 0 {
+        // Push subsequent entries
+        processes
+            .entry(process_entry_32.th32ParentProcessID)
+            .or_insert_with(Vec::new)
+            .push(process_entry_32.th32ProcessID);
+    }
+
+    unsafe {
+        CloseHandle(snapshot);
+    }
+
+    // Kill all children, then kills the process with the given `pid`
+    fn kill_pid(pid: u32, processes: &HashMap<ParentID, Vec<ChildID>>) -> Result<()> {
+        if let Some(children) = processes.get(&pid) {
+            for child in children {
+                kill_pid(*child, processes)?;
+            }
+        }
+        // open a handle to the given pid
+        let handle = unsafe { OpenProcess(PROCESS_TERMINATE, 0, pid) };
+        if handle.is_null() || 0 == unsafe { TerminateProcess(handle, 0) } {
+            // handle not terminated
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    kill_pid(pid, &processes)
+}
+
+/// Kill the process with the given `pid` by sending it `SIGKILL`. Its children are not
+/// signalled.
+#[cfg(target_os = "linux")]
+fn force_kill(pid: u32) -> Result<()> {
+    let result = unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+/// Waits `timeout`, then emits `ProcessEvent::TimedOut` (and optionally kills the process)
+/// unless `exited` shows the process already completed by then.
+///
+/// `exited` (set by `Process::watch_exit` right before it sends `Exit`/`CommandError`) is used
+/// instead of polling the OS by raw `pid`: a child that has exited but not yet been reaped
+/// still answers "alive" to a pid-based liveness check, and after it's reaped the same `pid`
+/// could have been recycled by an unrelated process.
+fn spawn_timeout_watcher(
+    exited: Arc<AtomicBool>,
+    pid: u32,
+    sender: Sender<ProcessEvent>,
+    timeout: std::time::Duration,
+    kill_on_timeout: bool,
+) {
+    spawn(move || {
+        std::thread::sleep(timeout);
+        if exited.load(Ordering::SeqCst) {
+            // The process already completed before the deadline; nothing to report.
+            return;
+        }
+        if sender.send(ProcessEvent::TimedOut).is_err() {
+            return;
+        }
+        if kill_on_timeout {
+            let _ = force_kill(pid);
+        }
+    });
+}
+
+/// Waits `timeout` in the background, then force-kills `pid` unless `exited` shows the
+/// process already completed by then. Used by `terminate_gracefully` so escalating from a
+/// polite signal to a forceful kill doesn't block the caller's thread.
+fn spawn_graceful_kill_watcher(exited: Arc<AtomicBool>, pid: u32, timeout: std::time::Duration) {
+    spawn(move || {
+        std::thread::sleep(timeout);
+        if exited.load(Ordering::SeqCst) {
+            return;
+        }
+        let _ = force_kill(pid);
+    });
+}
+
 #[derive(PartialEq, Eq, Debug)]
 enum SendResult {
     Abort,
     Ok,
 }
 
+/// Pulls the longest valid UTF8 prefix out of `pending`, draining those bytes out of it.
+///
+/// Any trailing bytes that look like the start of a multibyte character that simply
+/// hasn't arrived yet are left in `pending` so the next read can complete them. Set
+/// `is_eof` to `true` when the stream has ended, which turns such a trailing sequence
+/// into a genuine error instead of something left for next time.
+fn decode_utf8_prefix(
+    pending: &mut Vec<u8>,
+    is_eof: bool,
+) -> std::result::Result<String, std::str::Utf8Error> {
+    let valid_up_to = match std::str::from_utf8(pending) {
+        Ok(s) => s.len(),
+        Err(e) => match e.error_len() {
+            // A genuinely invalid byte sequence, not just a chunk boundary.
+            Some(_) => return Err(e),
+            // The tail of `pending` could still become valid once more bytes arrive,
+            // unless the stream has already ended, in which case it never will.
+            None if is_eof => return Err(e),
+            None => e.valid_up_to(),
+        },
+    };
+
+    let bytes: Vec<u8> = pending.drain(..valid_up_to).collect();
+    Ok(String::from_utf8(bytes).expect("valid_up_to bytes were already validated as utf8"))
+}
+
+/// Tries to send as much of `pending` as is valid UTF8 as a `ProcessEvent::Data`. See
+/// `decode_utf8_prefix` for how partial trailing sequences and EOF are handled.
 fn try_send_buffer(
-    buffer: &[u8],
+    pending: &mut Vec<u8>,
     channel: StdioChannel,
     sender: &Sender<ProcessEvent>,
+    is_eof: bool,
 ) -> SendResult {
-    let str = match std::str::from_utf8(buffer) {
-        Ok(s) => s,
+    let str = match decode_utf8_prefix(pending, is_eof) {
+        Ok(str) => str,
         Err(e) => {
             let _ = sender.send(ProcessEvent::Utf8Error(channel, e));
             return SendResult::Abort;
         }
     };
+
     if str.is_empty() {
-        println!("Aborting try_send_buffer because we're sending empty strings");
-        println!("Channel: {:?}", channel);
-        return SendResult::Abort;
-    }
-    if sender
-        .send(ProcessEvent::Data(channel, String::from(str)))
-        .is_err()
-    {
+        return SendResult::Ok;
+    }
+
+    if sender.send(ProcessEvent::Data(channel, str)).is_err() {
         SendResult::Abort
     } else {
         SendResult::Ok
     }
 }
 
+/// Tries to decode as much of `pending` as is valid UTF8, appends it to `line_buffer`, and
+/// sends one `ProcessEvent::Line` per newline-terminated line it now contains. Any trailing
+/// content without a newline stays in `line_buffer`, unless `is_eof` is set, in which case
+/// it is flushed as a final line.
+fn try_send_lines(
+    pending: &mut Vec<u8>,
+    line_buffer: &mut String,
+    channel: StdioChannel,
+    sender: &Sender<ProcessEvent>,
+    is_eof: bool,
+) -> SendResult {
+    match decode_utf8_prefix(pending, is_eof) {
+        Ok(str) => line_buffer.push_str(&str),
+        Err(e) => {
+            let _ = sender.send(ProcessEvent::Utf8Error(channel, e));
+            return SendResult::Abort;
+        }
+    }
+
+    while let Some(newline_pos) = line_buffer.find('\n') {
+        let line: String = line_buffer.drain(..=newline_pos).collect();
+        let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+        if sender
+            .send(ProcessEvent::Line(channel, line.to_string()))
+            .is_err()
+        {
+            return SendResult::Abort;
+        }
+    }
+
+    if is_eof && !line_buffer.is_empty() {
+        let line = std::mem::replace(line_buffer, String::new());
+        if sender.send(ProcessEvent::Line(channel, line)).is_err() {
+            return SendResult::Abort;
+        }
+    }
+
+    SendResult::Ok
+}
+
+/// Sends whatever output is left over after a child has already exited, honoring `mode`.
+///
+/// This mirrors the per-chunk handling in `create_reader`, but treats the buffer as
+/// complete: an incomplete UTF8 tail is always a genuine error, since no more bytes
+/// are coming.
+fn send_remaining_output(
+    buffer: &mut Vec<u8>,
+    channel: StdioChannel,
+    sender: &Sender<ProcessEvent>,
+    mode: ReaderMode,
+) -> SendResult {
+    match mode {
+        ReaderMode::Utf8 => try_send_buffer(buffer, channel, sender, true),
+        ReaderMode::Line => {
+            let mut line_buffer = String::new();
+            try_send_lines(buffer, &mut line_buffer, channel, sender, true)
+        }
+        ReaderMode::Raw => {
+            if sender
+                .send(ProcessEvent::RawData(
+                    channel,
+                    std::mem::replace(buffer, Vec::new()),
+                ))
+                .is_err()
+            {
+                SendResult::Abort
+            } else {
+                SendResult::Ok
+            }
+        }
+    }
+}
+
+/// Drives a single stdio stream to completion on its own dedicated thread.
+///
+/// On Linux, stdio reading goes through the shared `stdio_pool` instead (see
+/// `Process::spawn_stdio_reader`), so this is only used on other platforms.
+#[cfg(not(target_os = "linux"))]
 fn create_reader<T: Read + 'static>(
     mut stream: T,
     sender: Sender<ProcessEvent>,
     channel: StdioChannel,
+    mode: ReaderMode,
 ) -> impl FnOnce() {
     move || {
         let mut buffer = [0u8; 1024];
+        let mut pending = Vec::new();
+        let mut line_buffer = String::new();
         loop {
             match stream.read(&mut buffer[..]) {
                 Ok(0) => {
                     // if we read 0 bytes from the stream, that means the stream ended
+                    // flush whatever is left, treating a still-incomplete tail as a real error
+                    match mode {
+                        ReaderMode::Utf8 => {
+                            try_send_buffer(&mut pending, channel, &sender, true);
+                        }
+                        ReaderMode::Line => {
+                            try_send_lines(&mut pending, &mut line_buffer, channel, &sender, true);
+                        }
+                        ReaderMode::Raw => {}
+                    }
                     break;
                 }
                 Ok(n) => {
-                    if SendResult::Abort == try_send_buffer(&buffer[..n], channel, &sender) {
+                    let result = match mode {
+                        ReaderMode::Utf8 => {
+                            pending.extend_from_slice(&buffer[..n]);
+                            try_send_buffer(&mut pending, channel, &sender, false)
+                        }
+                        ReaderMode::Line => {
+                            pending.extend_from_slice(&buffer[..n]);
+                            try_send_lines(&mut pending, &mut line_buffer, channel, &sender, false)
+                        }
+                        ReaderMode::Raw => {
+                            if sender
+                                .send(ProcessEvent::RawData(channel, buffer[..n].to_vec()))
+                                .is_err()
+                            {
+                                SendResult::Abort
+                            } else {
+                                SendResult::Ok
+                            }
+                        }
+                    };
+                    if SendResult::Abort == result {
                         break;
                     }
                 }
@@ -346,6 +872,16 @@ pub enum ProcessEvent {
     /// Data is received on an StdioChannel. The String is the UTF8 interpretation of this data.
     Data(StdioChannel, String),
 
+    /// Data is received on an StdioChannel. The Vec<u8> is the unmodified data as read from the
+    /// stream. Only emitted for processes spawned with `CommandAsync::spawn_async_raw`.
+    RawData(StdioChannel, Vec<u8>),
+
+    /// A complete, newline-terminated line of output was received on an StdioChannel. Only
+    /// emitted for processes spawned with `CommandAsync::spawn_async_lines`. The trailing
+    /// newline (and preceding `\r`, if any) is stripped; a final unterminated line is flushed
+    /// as one of these when the stream hits EOF.
+    Line(StdioChannel, String),
+
     /// There was an issue with starting or closing a child process.
     CommandError(std::io::Error),
 
@@ -357,6 +893,9 @@ pub enum ProcessEvent {
 
     /// The process exited with the given ExitStatus.
     Exit(ExitStatus),
+
+    /// The process did not exit before the timeout set through `Process::with_timeout` elapsed.
+    TimedOut,
 }
 
 /// Describes what channel the ProcessEvent came from.
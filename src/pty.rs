@@ -0,0 +1,592 @@
+//! Pseudo-terminal allocation backing `CommandAsync::spawn_pty`.
+//!
+//! Programs that check `isatty()` (color output, progress bars, REPLs, `ssh`, shells) behave
+//! differently, or refuse to run at all, when attached to plain pipes the way `spawn_async`
+//! does. Allocating a real pseudo-terminal and handing the child its slave end as
+//! stdin/stdout/stderr makes it believe it's talking to an interactive terminal, while the
+//! master end gives us back a single readable/writable stream to drive through the existing
+//! `Data`/`Write`/`Evented` machinery.
+//!
+//! Linux uses `openpty`, where the master is a single fd that serves as both ends of the
+//! stream. Windows uses ConPTY, which is structurally different enough (a pseudo console
+//! object wired to two separate pipes, with the child created outside of `std::process::Command`
+//! entirely) that `Pty`/`PtyChild`/`Master` all carry a little extra platform-specific state to
+//! paper over the difference; see `windows::spawn` for the details.
+
+use std::fs::File;
+use std::io;
+#[cfg(not(target_os = "windows"))]
+use std::process::Child;
+use std::process::Command;
+
+/// The child process a `Pty` was allocated for, plus however this platform needs to wait for
+/// it: everywhere but Windows this is just a `std::process::Child` like any other spawn, but
+/// Windows ConPTY has to create the child through a raw Win32 call that bypasses `Command`
+/// entirely (see `windows::spawn`), so it can't produce one.
+pub(crate) enum PtyChild {
+    /// `openpty` plays nicely with `std::process::Command`, so everywhere but Windows this is
+    /// a normal `Child` like any other spawn.
+    #[cfg(not(target_os = "windows"))]
+    Std(Child),
+    /// ConPTY needs its pseudo console handed to `CreateProcessW` through a `STARTUPINFOEXW`
+    /// attribute that `std::process::Command` has no API for setting.
+    #[cfg(target_os = "windows")]
+    ConPty(windows::ConPtyChild),
+}
+
+impl PtyChild {
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            PtyChild::Std(child) => child.id(),
+            #[cfg(target_os = "windows")]
+            PtyChild::ConPty(child) => child.pid,
+        }
+    }
+}
+
+/// Blocks until `child` exits and reports its exit status - the ConPTY equivalent of
+/// `std::process::Child::wait`, for the one platform whose pty-backed child isn't a real
+/// `Child`.
+#[cfg(target_os = "windows")]
+pub(crate) fn wait_for_exit(child: PtyChild) -> io::Result<std::process::ExitStatus> {
+    let PtyChild::ConPty(child) = child;
+    windows::wait(child)
+}
+
+/// A pseudo-terminal's master end, paired with the child it was allocated for.
+pub(crate) struct Pty {
+    pub(crate) master: Master,
+    pub(crate) child: PtyChild,
+    /// The independent handle the stdio pool/reader thread should read from, for platforms
+    /// where reading and writing the pty don't share a single handle the way a Unix pty
+    /// master fd does (see `reopen_reader`). `None` means the caller should fall back to
+    /// `reopen_reader(&master)` instead.
+    pub(crate) reader: Option<File>,
+}
+
+/// The write (and, on platforms where a single handle serves both roles, read) end of an
+/// allocated pty, plus whatever else a platform's resize/teardown need beyond that handle.
+pub(crate) struct Master {
+    pub(crate) file: File,
+    #[cfg(target_os = "windows")]
+    console: windows::PseudoConsole,
+}
+
+impl io::Write for Master {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn spawn(command: &mut Command, size: (u16, u16)) -> io::Result<Pty> {
+    linux::spawn(command, size)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn resize(master: &Master, size: (u16, u16)) -> io::Result<()> {
+    linux::resize(&master.file, size)
+}
+
+/// Opens a second, independent handle onto the same pty master that `master` refers to, for
+/// handing to the stdio reader pool.
+///
+/// This must NOT be a plain `dup()`: a `dup`'d fd shares its *open file description* with the
+/// original, including the `O_NONBLOCK` flag the reader pool sets on whatever it's given,
+/// which would silently make writes through `master` non-blocking too. Reopening the fd's
+/// `/proc/self/fd` entry instead gives back a genuinely independent open file description.
+#[cfg(target_os = "linux")]
+pub(crate) fn reopen_reader(master: &Master) -> io::Result<File> {
+    linux::reopen_reader(&master.file)
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn spawn(command: &mut Command, size: (u16, u16)) -> io::Result<Pty> {
+    windows::spawn(command, size)
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn resize(master: &Master, size: (u16, u16)) -> io::Result<()> {
+    master.console.resize(size)
+}
+
+/// ConPTY's master is two separate pipes, so `Pty::reader` is always populated by `spawn` and
+/// this is never actually called; it exists so `Process::from_pty`'s fallback arm still type
+/// checks on this platform.
+#[cfg(target_os = "windows")]
+pub(crate) fn reopen_reader(_master: &Master) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "ConPTY pty masters are never missing an independent reader handle",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub(crate) fn spawn(_command: &mut Command, _size: (u16, u16)) -> io::Result<Pty> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "spawn_pty is only implemented on Linux and Windows for now",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub(crate) fn resize(_master: &Master, _size: (u16, u16)) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "spawn_pty is only implemented on Linux and Windows for now",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub(crate) fn reopen_reader(_master: &Master) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "spawn_pty is only implemented on Linux and Windows for now",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Master, Pty, PtyChild};
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    pub(super) fn spawn(command: &mut Command, size: (u16, u16)) -> io::Result<Pty> {
+        let (master_fd, slave_fd) = open_pty(size)?;
+        set_cloexec(master_fd)?;
+
+        // Three independent fds, since `Command` takes ownership of (and eventually closes)
+        // each `Stdio` it's given; one `dup`'d slave fd each keeps stdin/stdout/stderr
+        // pointing at the same pty slave without a double-close.
+        let stdout_fd = dup(slave_fd)?;
+        let stderr_fd = dup(slave_fd)?;
+
+        // Mark all three CLOEXEC until the moment `Command` dup2's them onto the child's
+        // 0/1/2 (which always clears CLOEXEC on the resulting descriptor): otherwise, if
+        // another thread forks an unrelated child in the window between here and our own
+        // fork below, that unrelated child inherits our whole fd table and leaks these pty
+        // fds into a process that was never meant to see them - the same class of bug fixed
+        // for the `O_NONBLOCK` sharing in 309cfc9.
+        set_cloexec(slave_fd)?;
+        set_cloexec(stdout_fd)?;
+        set_cloexec(stderr_fd)?;
+
+        command.stdin(unsafe { Stdio::from_raw_fd(slave_fd) });
+        command.stdout(unsafe { Stdio::from_raw_fd(stdout_fd) });
+        command.stderr(unsafe { Stdio::from_raw_fd(stderr_fd) });
+
+        unsafe {
+            // Safety: runs in the forked child between `fork` and `exec`, after `Command` has
+            // already dup2'd the pty slave onto fd 0/1/2, so only the async-signal-safe
+            // syscalls below are needed to make it the child's controlling terminal.
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let child = command.spawn()?;
+        let master = unsafe { File::from_raw_fd(master_fd) };
+        Ok(Pty {
+            master: Master { file: master },
+            child: PtyChild::Std(child),
+            reader: None,
+        })
+    }
+
+    pub(super) fn resize(master: &File, size: (u16, u16)) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let winsize = to_winsize(size);
+        if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ as _, &winsize) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn reopen_reader(master: &File) -> io::Result<File> {
+        use std::os::unix::io::AsRawFd;
+
+        File::open(format!("/proc/self/fd/{}", master.as_raw_fd()))
+    }
+
+    fn open_pty(size: (u16, u16)) -> io::Result<(RawFd, RawFd)> {
+        let mut master: RawFd = -1;
+        let mut slave: RawFd = -1;
+        let winsize = to_winsize(size);
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &winsize,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((master, slave))
+    }
+
+    fn to_winsize((rows, cols): (u16, u16)) -> libc::winsize {
+        let mut winsize: libc::winsize = unsafe { mem::zeroed() };
+        winsize.ws_row = rows;
+        winsize.ws_col = cols;
+        winsize
+    }
+
+    fn dup(fd: RawFd) -> io::Result<RawFd> {
+        let result = unsafe { libc::dup(fd) };
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    fn set_cloexec(fd: RawFd) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use super::{Master, Pty, PtyChild};
+    use std::collections::BTreeMap;
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::FromRawHandle;
+    use std::process::Command;
+    use std::ptr;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::winerror::S_OK;
+    use winapi::um::consoleapi::{ClosePseudoConsole, CreatePseudoConsole, ResizePseudoConsole};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::namedpipeapi::CreatePipe;
+    use winapi::um::processthreadsapi::{
+        CreateProcessW, DeleteProcThreadAttributeList, GetExitCodeProcess,
+        InitializeProcThreadAttributeList, UpdateProcThreadAttribute, PROCESS_INFORMATION,
+        STARTUPINFOEXW,
+    };
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::{CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, INFINITE};
+    use winapi::um::wincontypes::{COORD, HPCON};
+    use winapi::um::winnt::HANDLE;
+
+    /// Microsoft's documented attribute id for attaching a pseudo console to a process's
+    /// `STARTUPINFOEX`. Not exposed by `winapi`; see the ConPTY sample at
+    /// https://learn.microsoft.com/en-us/windows/console/creating-a-pseudoconsole-session.
+    const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x0002_0016;
+
+    /// A ConPTY-backed child. Unlike every other platform, it can't be represented as a
+    /// `std::process::Child`: attaching a pseudo console requires a raw `CreateProcessW` call
+    /// through a `STARTUPINFOEX` attribute `Command` has no public API for setting, so the
+    /// child is created outside of `Command` entirely.
+    pub(crate) struct ConPtyChild {
+        process: HANDLE,
+        pub(crate) pid: u32,
+    }
+
+    // `HANDLE` is just a `*mut c_void`; the handle itself has no thread affinity, so it's safe
+    // to hand off to the background thread that waits on it.
+    unsafe impl Send for ConPtyChild {}
+
+    /// Waits for `child` to exit and reports its exit status - the ConPTY equivalent of
+    /// `std::process::Child::wait`, for the one platform that can't produce a real `Child`.
+    pub(crate) fn wait(child: ConPtyChild) -> io::Result<std::process::ExitStatus> {
+        use std::os::windows::process::ExitStatusExt;
+
+        unsafe {
+            WaitForSingleObject(child.process, INFINITE);
+            let mut code: DWORD = 0;
+            let got_code = GetExitCodeProcess(child.process, &mut code);
+            CloseHandle(child.process);
+            if got_code == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(std::process::ExitStatus::from_raw(code))
+            }
+        }
+    }
+
+    pub(crate) struct PseudoConsole(HPCON);
+
+    // Same rationale as `ConPtyChild`: `HPCON` is an opaque, thread-agnostic handle.
+    unsafe impl Send for PseudoConsole {}
+
+    impl PseudoConsole {
+        pub(crate) fn resize(&self, size: (u16, u16)) -> io::Result<()> {
+            let result = unsafe { ResizePseudoConsole(self.0, to_coord(size)) };
+            if result == S_OK {
+                Ok(())
+            } else {
+                Err(io::Error::from_raw_os_error(result))
+            }
+        }
+    }
+
+    impl Drop for PseudoConsole {
+        fn drop(&mut self) {
+            unsafe {
+                ClosePseudoConsole(self.0);
+            }
+        }
+    }
+
+    pub(super) fn spawn(command: &mut Command, size: (u16, u16)) -> io::Result<Pty> {
+        let (input_read, input_write) = create_pipe()?;
+        let (output_read, output_write) = create_pipe()?;
+
+        let mut console_handle: HPCON = ptr::null_mut();
+        let result = unsafe {
+            CreatePseudoConsole(
+                to_coord(size),
+                input_read,
+                output_write,
+                0,
+                &mut console_handle,
+            )
+        };
+        // `CreatePseudoConsole` duplicates the handles it needs internally, so our copies of
+        // the ends it now owns must be closed here regardless of the outcome.
+        unsafe {
+            CloseHandle(input_read);
+            CloseHandle(output_write);
+        }
+        if result != S_OK {
+            unsafe {
+                CloseHandle(input_write);
+                CloseHandle(output_read);
+            }
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        let console = PseudoConsole(console_handle);
+
+        match create_process(command, console_handle) {
+            Ok((process, pid)) => Ok(Pty {
+                master: Master {
+                    file: unsafe { File::from_raw_handle(input_write as _) },
+                    console,
+                },
+                child: PtyChild::ConPty(ConPtyChild { process, pid }),
+                reader: Some(unsafe { File::from_raw_handle(output_read as _) }),
+            }),
+            Err(e) => {
+                unsafe {
+                    CloseHandle(input_write);
+                    CloseHandle(output_read);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn create_pipe() -> io::Result<(HANDLE, HANDLE)> {
+        let mut read_handle: HANDLE = ptr::null_mut();
+        let mut write_handle: HANDLE = ptr::null_mut();
+        let result = unsafe { CreatePipe(&mut read_handle, &mut write_handle, ptr::null_mut(), 0) };
+        if result == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok((read_handle, write_handle))
+        }
+    }
+
+    fn to_coord((rows, cols): (u16, u16)) -> COORD {
+        COORD {
+            X: cols as i16,
+            Y: rows as i16,
+        }
+    }
+
+    /// Spawns `command` with `console` attached as its pseudo console, via a raw
+    /// `CreateProcessW` call - the only way to set the `PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE`
+    /// attribute that `Command` has no public API for.
+    fn create_process(command: &mut Command, console: HPCON) -> io::Result<(HANDLE, u32)> {
+        let mut attr_list_size: usize = 0;
+        unsafe {
+            InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut attr_list_size);
+        }
+        let mut attr_list_buffer = vec![0u8; attr_list_size];
+        let attr_list = attr_list_buffer.as_mut_ptr() as _;
+        if unsafe { InitializeProcThreadAttributeList(attr_list, 1, 0, &mut attr_list_size) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut console = console;
+        let update_result = unsafe {
+            UpdateProcThreadAttribute(
+                attr_list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+                &mut console as *mut HPCON as _,
+                mem::size_of::<HPCON>(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if update_result == 0 {
+            unsafe {
+                DeleteProcThreadAttributeList(attr_list);
+            }
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut startup_info: STARTUPINFOEXW = unsafe { mem::zeroed() };
+        startup_info.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as DWORD;
+        startup_info.lpAttributeList = attr_list;
+        let mut process_info: PROCESS_INFORMATION = unsafe { mem::zeroed() };
+
+        let mut command_line = build_command_line(command);
+        let mut environment = build_environment_block(command);
+        let current_dir = command.get_current_dir().map(to_wide_null);
+
+        let success = unsafe {
+            CreateProcessW(
+                ptr::null(),
+                command_line.as_mut_ptr(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+                environment
+                    .as_mut()
+                    .map_or(ptr::null_mut(), |e| e.as_mut_ptr() as _),
+                current_dir.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+                &mut startup_info.StartupInfo,
+                &mut process_info,
+            )
+        };
+
+        unsafe {
+            DeleteProcThreadAttributeList(attr_list);
+        }
+
+        if success == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            CloseHandle(process_info.hThread);
+        }
+        Ok((process_info.hProcess, process_info.dwProcessId))
+    }
+
+    /// Builds a `CreateProcessW`-ready, NUL-terminated wide command line out of the program and
+    /// arguments `command` was configured with, quoting each argument the way
+    /// `CommandLineToArgvW` expects to parse it back apart (the same algorithm the Windows CRT
+    /// and `std`'s own `Command` use internally - reimplemented here since `Command` doesn't
+    /// expose its command line for us to reuse).
+    fn build_command_line(command: &Command) -> Vec<u16> {
+        let mut line = String::new();
+        for (i, arg) in std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .enumerate()
+        {
+            if i > 0 {
+                line.push(' ');
+            }
+            quote_arg(arg, &mut line);
+        }
+        to_wide_null(line)
+    }
+
+    fn quote_arg(arg: &OsStr, out: &mut String) {
+        let arg = arg.to_string_lossy();
+        let needs_quotes =
+            arg.is_empty() || arg.contains(|c: char| c == ' ' || c == '\t' || c == '"');
+        if !needs_quotes {
+            out.push_str(&arg);
+            return;
+        }
+        out.push('"');
+        let mut chars = arg.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                let mut backslashes = 1;
+                while chars.peek() == Some(&'\\') {
+                    backslashes += 1;
+                    chars.next();
+                }
+                let followed_by_quote = chars.peek() == Some(&'"') || chars.peek().is_none();
+                let run = if followed_by_quote {
+                    backslashes * 2
+                } else {
+                    backslashes
+                };
+                out.extend(std::iter::repeat('\\').take(run));
+            } else if c == '"' {
+                out.push('\\');
+                out.push('"');
+            } else {
+                out.push(c);
+            }
+        }
+        out.push('"');
+    }
+
+    /// Builds the `CreateProcessW` environment block for `command`: the parent's environment
+    /// with whatever `.env()`/`.env_remove()` changes `command` was configured with applied on
+    /// top, matching `std::process::Command`'s own semantics. Returns `None` if `command`
+    /// didn't customize the environment, so `CreateProcessW` just inherits the parent's as-is.
+    fn build_environment_block(command: &Command) -> Option<Vec<u16>> {
+        let mut vars: BTreeMap<_, _> = std::env::vars_os().collect();
+        let mut customized = false;
+        for (key, value) in command.get_envs() {
+            customized = true;
+            match value {
+                Some(value) => {
+                    vars.insert(key.to_os_string(), value.to_os_string());
+                }
+                None => {
+                    vars.remove(key);
+                }
+            }
+        }
+        if !customized {
+            return None;
+        }
+
+        let mut block = Vec::new();
+        for (key, value) in vars {
+            block.extend(key.encode_wide());
+            block.push(b'=' as u16);
+            block.extend(value.encode_wide());
+            block.push(0);
+        }
+        block.push(0);
+        Some(block)
+    }
+
+    fn to_wide_null(value: impl AsRef<OsStr>) -> Vec<u16> {
+        let mut wide: Vec<u16> = value.as_ref().encode_wide().collect();
+        wide.push(0);
+        wide
+    }
+}
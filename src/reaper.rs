@@ -0,0 +1,162 @@
+//! A single global, SIGCHLD-driven reaper shared by every spawned child process.
+//!
+//! Waiting on a child the naive way means blocking a dedicated thread in `wait()` for as
+//! long as that child lives, so a server managing many children pays for one parked
+//! thread per process. Instead, every child is registered here and a single background
+//! thread is woken up whenever SIGCHLD fires, then drains the whole queue with
+//! non-blocking waits.
+//!
+//! A signal handler can't safely do much more than set a flag, so SIGCHLD delivery is
+//! bounced through a self-pipe: the handler writes a single byte to the pipe, and the
+//! reaper thread has that pipe's read end registered with its own `mio::Poll`. Because
+//! one SIGCHLD can coalesce several child deaths, every wakeup re-scans the entire queue
+//! rather than stopping at the first child that's exited.
+
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::Sender;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::spawn;
+use ProcessEvent;
+
+struct Orphan {
+    child: Child,
+    sender: Sender<ProcessEvent>,
+    exited: Arc<AtomicBool>,
+}
+
+struct Reaper {
+    orphans: Mutex<Vec<Orphan>>,
+    wake_write: RawFd,
+}
+
+static mut REAPER: Option<Reaper> = None;
+static REAPER_INIT: Once = Once::new();
+static mut WAKE_WRITE_FD: RawFd = -1;
+
+/// Registers `child` with the global reaper. `sender` receives a `ProcessEvent::Exit` once
+/// the child has been waited on, matching what the old per-process wait thread used to send.
+/// `exited` is set to `true` right beforehand, so callers (e.g. `Process::with_timeout`) can
+/// tell a real completion apart from the child's `pid` merely looking alive.
+pub(crate) fn register(child: Child, sender: Sender<ProcessEvent>, exited: Arc<AtomicBool>) {
+    let reaper = reaper();
+    reaper
+        .orphans
+        .lock()
+        .expect("reaper orphan queue poisoned")
+        .push(Orphan {
+            child,
+            sender,
+            exited,
+        });
+    // The child may have already exited by the time it's registered; prod the reaper
+    // thread instead of waiting for the next unrelated SIGCHLD to notice it.
+    wake(reaper.wake_write);
+}
+
+fn reaper() -> &'static Reaper {
+    REAPER_INIT.call_once(|| {
+        let (read_fd, write_fd) = self_pipe();
+        unsafe {
+            WAKE_WRITE_FD = write_fd;
+            REAPER = Some(Reaper {
+                orphans: Mutex::new(Vec::new()),
+                wake_write: write_fd,
+            });
+            libc::signal(libc::SIGCHLD, handle_sigchld as libc::sighandler_t);
+        }
+        spawn(move || reaper_loop(read_fd));
+    });
+    unsafe {
+        REAPER
+            .as_ref()
+            .expect("reaper is initialized by call_once above")
+    }
+}
+
+fn self_pipe() -> (RawFd, RawFd) {
+    let mut fds = [0i32; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        panic!(
+            "Could not create reaper self-pipe: {}",
+            io::Error::last_os_error()
+        );
+    }
+    for fd in &fds {
+        unsafe {
+            let flags = libc::fcntl(*fd, libc::F_GETFL);
+            libc::fcntl(*fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+    (fds[0], fds[1])
+}
+
+extern "C" fn handle_sigchld(_signal: i32) {
+    // Async-signal-safe: all we do here is wake the reaper thread up, the actual
+    // waitpid-ing happens there.
+    unsafe { wake(WAKE_WRITE_FD) };
+}
+
+fn wake(write_fd: RawFd) {
+    if write_fd < 0 {
+        return;
+    }
+    unsafe {
+        libc::write(write_fd, [0u8].as_ptr() as *const _, 1);
+    }
+}
+
+fn reaper_loop(read_fd: RawFd) -> ! {
+    let poll = Poll::new().expect("Could not create reaper Poll");
+    poll.register(
+        &EventedFd(&read_fd),
+        Token(0),
+        Ready::readable(),
+        PollOpt::edge(),
+    )
+    .expect("Could not register reaper self-pipe");
+
+    let mut read_end = unsafe { File::from_raw_fd(read_fd) };
+    let mut events = Events::with_capacity(16);
+    let mut drain_buffer = [0u8; 128];
+    loop {
+        poll.poll(&mut events, None)
+            .expect("Could not poll reaper self-pipe");
+        // Drain every queued wakeup byte so the next edge-triggered notification isn't missed.
+        while let Ok(n) = read_end.read(&mut drain_buffer) {
+            if n == 0 {
+                break;
+            }
+        }
+        reap_all();
+    }
+}
+
+fn reap_all() {
+    let reaper = reaper();
+    let mut orphans = reaper.orphans.lock().expect("reaper orphan queue poisoned");
+    let mut i = 0;
+    while i < orphans.len() {
+        match orphans[i].child.try_wait() {
+            Ok(Some(status)) => {
+                let orphan = orphans.remove(i);
+                orphan.exited.store(true, Ordering::SeqCst);
+                let _ = orphan.sender.send(ProcessEvent::Exit(status));
+            }
+            Ok(None) => i += 1,
+            Err(e) => {
+                // The child can no longer be waited on (e.g. ECHILD); stop tracking it
+                // rather than spinning on it forever.
+                let orphan = orphans.remove(i);
+                orphan.exited.store(true, Ordering::SeqCst);
+                let _ = orphan.sender.send(ProcessEvent::CommandError(e));
+            }
+        }
+    }
+}
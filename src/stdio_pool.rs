@@ -0,0 +1,266 @@
+//! A small, bounded pool of background threads that services every spawned process's stdio
+//! streams, instead of parking one dedicated thread per stdout/stderr/pty stream.
+//!
+//! Each worker owns its own `mio::Poll`, plus a self-pipe (the same trick `reaper` uses for
+//! SIGCHLD) so a new stream can be registered onto an already-running worker without it having
+//! to wake up on its own. Streams are handed to workers round-robin, so a handful of threads
+//! can service hundreds of concurrently spawned processes instead of one thread each.
+
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::Sender;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread::spawn;
+use {try_send_buffer, try_send_lines, ProcessEvent, ReaderMode, SendResult, StdioChannel};
+
+/// Default number of background threads servicing stdio streams, used unless
+/// `set_pool_size` is called before the first stream is registered. Kept small, since each
+/// worker's `Poll` can service an effectively unbounded number of streams; this just bounds
+/// how much read work can happen concurrently.
+const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Current pool size; only read once, by `pool()`'s `call_once`, so changing it after the
+/// pool has already been created has no effect.
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_POOL_SIZE);
+
+/// Overrides how many background threads service stdio streams; see `lib::set_stdio_pool_size`.
+///
+/// Has no effect once the pool has already been created by the first registered stream, since
+/// the size is only read by the lazy `Once` init in `pool()`.
+pub(crate) fn set_pool_size(size: usize) {
+    POOL_SIZE.store(size.max(1), Ordering::Relaxed);
+}
+
+/// Reserved token for a worker's own wake-up self-pipe; real streams start at `Token(1)`.
+const WAKE_TOKEN: Token = Token(0);
+
+struct StreamState {
+    file: File,
+    sender: Sender<ProcessEvent>,
+    channel: StdioChannel,
+    mode: ReaderMode,
+    pending: Vec<u8>,
+    line_buffer: String,
+}
+
+struct Worker {
+    pending_registrations: Mutex<Vec<StreamState>>,
+    wake_write: RawFd,
+}
+
+static mut POOL: Option<Vec<Worker>> = None;
+static POOL_INIT: Once = Once::new();
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hands `file` off to the pool to be read from in the background. Decoded output (per
+/// `mode`) is forwarded through `sender`, exactly as `create_reader` would send it.
+pub(crate) fn register_stream(
+    file: File,
+    sender: Sender<ProcessEvent>,
+    channel: StdioChannel,
+    mode: ReaderMode,
+) {
+    set_nonblocking(file.as_raw_fd());
+
+    let workers = pool();
+    let index = NEXT_WORKER.fetch_add(1, Ordering::Relaxed) % workers.len();
+    let worker = &workers[index];
+    worker
+        .pending_registrations
+        .lock()
+        .expect("stdio pool registration queue poisoned")
+        .push(StreamState {
+            file,
+            sender,
+            channel,
+            mode,
+            pending: Vec::new(),
+            line_buffer: String::new(),
+        });
+    wake(worker.wake_write);
+}
+
+fn pool() -> &'static Vec<Worker> {
+    POOL_INIT.call_once(|| {
+        let pool_size = POOL_SIZE.load(Ordering::Relaxed);
+        let mut workers = Vec::with_capacity(pool_size);
+        for index in 0..pool_size {
+            let (read_fd, write_fd) = self_pipe();
+            workers.push(Worker {
+                pending_registrations: Mutex::new(Vec::new()),
+                wake_write: write_fd,
+            });
+            spawn(move || worker_loop(index, read_fd));
+        }
+        unsafe {
+            POOL = Some(workers);
+        }
+    });
+    unsafe {
+        POOL.as_ref()
+            .expect("pool is initialized by call_once above")
+    }
+}
+
+fn self_pipe() -> (RawFd, RawFd) {
+    let mut fds = [0i32; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        panic!(
+            "Could not create stdio pool wake pipe: {}",
+            io::Error::last_os_error()
+        );
+    }
+    // Both ends must be non-blocking: `worker_loop`'s drain loop below reads until it sees
+    // `WouldBlock`, which a blocking fd never returns once the currently-buffered wakeup
+    // byte(s) are gone - it would just hang waiting for the next write instead, the same way
+    // `reaper`'s self-pipe needs this.
+    for fd in &fds {
+        unsafe {
+            let flags = libc::fcntl(*fd, libc::F_GETFL);
+            libc::fcntl(*fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+    (fds[0], fds[1])
+}
+
+fn set_nonblocking(fd: RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
+fn wake(write_fd: RawFd) {
+    unsafe {
+        libc::write(write_fd, [0u8].as_ptr() as *const _, 1);
+    }
+}
+
+fn worker_loop(index: usize, wake_read_fd: RawFd) -> ! {
+    let worker = &pool()[index];
+    let poll = Poll::new().expect("Could not create stdio pool Poll");
+    poll.register(
+        &EventedFd(&wake_read_fd),
+        WAKE_TOKEN,
+        Ready::readable(),
+        PollOpt::edge(),
+    )
+    .expect("Could not register stdio pool wake pipe");
+
+    let mut wake_read = unsafe { File::from_raw_fd(wake_read_fd) };
+    let mut streams: HashMap<Token, StreamState> = HashMap::new();
+    let mut next_token = 1usize;
+    let mut events = Events::with_capacity(64);
+    let mut drain_buffer = [0u8; 128];
+    loop {
+        poll.poll(&mut events, None)
+            .expect("Could not poll stdio pool");
+        for event in &events {
+            let token = event.token();
+            if token == WAKE_TOKEN {
+                // Drain every queued wakeup byte so the next edge-triggered notification
+                // isn't missed, then pick up whatever streams were registered since.
+                while let Ok(n) = wake_read.read(&mut drain_buffer) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                let mut pending = worker
+                    .pending_registrations
+                    .lock()
+                    .expect("stdio pool registration queue poisoned");
+                for state in pending.drain(..) {
+                    let token = Token(next_token);
+                    next_token += 1;
+                    let fd = state.file.as_raw_fd();
+                    poll.register(&EventedFd(&fd), token, Ready::readable(), PollOpt::edge())
+                        .expect("Could not register stdio stream");
+                    streams.insert(token, state);
+                }
+                continue;
+            }
+
+            let done = match streams.get_mut(&token) {
+                Some(state) => pump(state),
+                None => continue,
+            };
+            if done {
+                if let Some(state) = streams.remove(&token) {
+                    let fd = state.file.as_raw_fd();
+                    let _ = poll.deregister(&EventedFd(&fd));
+                }
+            }
+        }
+    }
+}
+
+/// Reads from `state.file` until it would block or the stream ends, forwarding decoded output
+/// through `state.sender`, exactly like `create_reader`'s per-chunk handling. Returns `true`
+/// once the stream is finished and should be deregistered and dropped.
+fn pump(state: &mut StreamState) -> bool {
+    let mut buffer = [0u8; 4096];
+    loop {
+        match state.file.read(&mut buffer) {
+            Ok(0) => {
+                match state.mode {
+                    ReaderMode::Utf8 => {
+                        try_send_buffer(&mut state.pending, state.channel, &state.sender, true);
+                    }
+                    ReaderMode::Line => {
+                        try_send_lines(
+                            &mut state.pending,
+                            &mut state.line_buffer,
+                            state.channel,
+                            &state.sender,
+                            true,
+                        );
+                    }
+                    ReaderMode::Raw => {}
+                }
+                return true;
+            }
+            Ok(n) => {
+                let result = match state.mode {
+                    ReaderMode::Utf8 => {
+                        state.pending.extend_from_slice(&buffer[..n]);
+                        try_send_buffer(&mut state.pending, state.channel, &state.sender, false)
+                    }
+                    ReaderMode::Line => {
+                        state.pending.extend_from_slice(&buffer[..n]);
+                        try_send_lines(
+                            &mut state.pending,
+                            &mut state.line_buffer,
+                            state.channel,
+                            &state.sender,
+                            false,
+                        )
+                    }
+                    ReaderMode::Raw => {
+                        if state
+                            .sender
+                            .send(ProcessEvent::RawData(state.channel, buffer[..n].to_vec()))
+                            .is_err()
+                        {
+                            SendResult::Abort
+                        } else {
+                            SendResult::Ok
+                        }
+                    }
+                };
+                if result == SendResult::Abort {
+                    return true;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                let _ = state.sender.send(ProcessEvent::IoError(state.channel, e));
+                return true;
+            }
+        }
+    }
+}
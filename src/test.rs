@@ -1,7 +1,63 @@
 use mio::{Evented, Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::channel;
 use std::process::{Command, Stdio};
 use std::sync::mpsc::TryRecvError;
-use {CommandAsync, ProcessEvent};
+use {decode_utf8_prefix, try_send_buffer, CommandAsync, ProcessEvent, StdioChannel};
+
+#[test]
+fn test_decode_utf8_prefix_buffers_split_multibyte_char() {
+    // The euro sign is 3 bytes (0xE2 0x82 0xAC); split it across two reads the way a pipe
+    // might deliver it in separate chunks.
+    let euro = "€".as_bytes();
+    assert_eq!(euro.len(), 3);
+
+    let mut pending = vec![b'a', euro[0], euro[1]];
+    let decoded = decode_utf8_prefix(&mut pending, false).expect("should not be an error yet");
+    assert_eq!(decoded, "a");
+    assert_eq!(
+        pending,
+        vec![euro[0], euro[1]],
+        "partial char stays buffered"
+    );
+
+    pending.push(euro[2]);
+    let decoded = decode_utf8_prefix(&mut pending, false).expect("char is now complete");
+    assert_eq!(decoded, "€");
+    assert!(pending.is_empty());
+}
+
+#[test]
+fn test_decode_utf8_prefix_rejects_incomplete_sequence_at_eof() {
+    let euro = "€".as_bytes();
+    let mut pending = vec![euro[0], euro[1]];
+    decode_utf8_prefix(&mut pending, true).expect_err("a stream that ends mid-char is an error");
+}
+
+#[test]
+fn test_try_send_buffer_forwards_only_complete_chars() {
+    let euro = "€".as_bytes();
+    let mut pending = vec![euro[0], euro[1]];
+    let (sender, receiver) = channel();
+
+    try_send_buffer(&mut pending, StdioChannel::Stdout, &sender, false);
+    match receiver.try_recv() {
+        Err(TryRecvError::Empty) => {}
+        other => panic!(
+            "expected nothing to be sent while the char is still incomplete, got {:?}",
+            other
+        ),
+    }
+
+    pending.push(euro[2]);
+    try_send_buffer(&mut pending, StdioChannel::Stdout, &sender, false);
+    match receiver
+        .try_recv()
+        .expect("the now-complete char should be sent")
+    {
+        ProcessEvent::Data(StdioChannel::Stdout, s) => assert_eq!(s, "€"),
+        other => panic!("unexpected event: {:?}", other),
+    }
+}
 
 #[test]
 fn test_ping() {
@@ -47,6 +103,416 @@ fn test_ping() {
     }
 }
 
+#[test]
+fn test_ping_raw() {
+    let mut process = Command::new("ping");
+    if cfg!(target_os = "linux") {
+        process.arg("-c").arg("4");
+    }
+    let mut process = process
+        .arg("8.8.8.8")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async_raw()
+        .expect("Could not spawn process");
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+    let mut saw_raw_data = false;
+    'outer: loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                println!("{:?}", result);
+
+                match result {
+                    ProcessEvent::Data(_, _) => {
+                        panic!("spawn_async_raw should never emit ProcessEvent::Data")
+                    }
+                    ProcessEvent::RawData(_, _) => saw_raw_data = true,
+                    ProcessEvent::Exit(_exit_status) => {
+                        break 'outer;
+                    }
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    assert!(
+        saw_raw_data,
+        "expected at least one ProcessEvent::RawData before the process exited"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reaper_reports_exit_of_already_finished_process() {
+    // `true` exits essentially immediately, so by the time it's registered with the reaper
+    // it's likely already a zombie waiting to be reaped - exercising the "child may have
+    // already exited by the time it's registered" path in reaper::register.
+    let mut process = Command::new("true")
+        .spawn_async()
+        .expect("Could not spawn process");
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+    loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                match result {
+                    ProcessEvent::Exit(exit_status) => {
+                        assert!(exit_status.success());
+                        return;
+                    }
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_terminate_gracefully_does_not_block_and_forwards_events() {
+    let mut process = Command::new("ping");
+    let mut process = process
+        .arg("8.8.8.8")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async()
+        .expect("Could not spawn process");
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+
+    let timeout = std::time::Duration::from_millis(500);
+    let before = std::time::Instant::now();
+    process
+        .terminate_gracefully(timeout)
+        .expect("Could not send polite termination request");
+    assert!(
+        before.elapsed() < timeout,
+        "terminate_gracefully should return long before its timeout elapses"
+    );
+
+    // The caller's own event loop should still see everything the process produces while
+    // winding down (and, eventually, whichever Exit the polite signal or the background
+    // force-kill results in) - terminate_gracefully must not have drained the channel itself.
+    loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                println!("{:?}", result);
+
+                match result {
+                    ProcessEvent::Exit(_exit_status) => return,
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_with_timeout_kills_long_running_process() {
+    let mut process = Command::new("ping")
+        .arg("8.8.8.8")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async()
+        .expect("Could not spawn process");
+    process = process.with_timeout(std::time::Duration::from_millis(200), true);
+
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+    let mut saw_timed_out = false;
+    loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                println!("{:?}", result);
+
+                match result {
+                    ProcessEvent::TimedOut => saw_timed_out = true,
+                    ProcessEvent::Exit(_exit_status) => {
+                        assert!(saw_timed_out, "Exit should be preceded by TimedOut here");
+                        return;
+                    }
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_with_timeout_does_not_fire_for_a_process_that_already_exited() {
+    // `true` exits essentially immediately; a quick-exiting process should never be reported
+    // as timed out even with a short timeout, including the window where it has exited but
+    // not yet been reaped by the background reaper.
+    let process = Command::new("true")
+        .spawn_async()
+        .expect("Could not spawn process");
+    let mut process = process.with_timeout(std::time::Duration::from_millis(50), true);
+
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+    loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                match result {
+                    ProcessEvent::TimedOut => {
+                        panic!("a process that already exited should not be reported as timed out")
+                    }
+                    ProcessEvent::Exit(exit_status) => {
+                        assert!(exit_status.success());
+                        return;
+                    }
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_spawn_async_lines_frames_complete_and_trailing_lines() {
+    // The last line has no trailing newline, exercising the eof flush in try_send_lines.
+    let mut process = Command::new("sh")
+        .arg("-c")
+        .arg("printf 'one\\ntwo\\nthree'")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async_lines()
+        .expect("Could not spawn process");
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+    let mut lines = Vec::new();
+    'outer: loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                println!("{:?}", result);
+
+                match result {
+                    ProcessEvent::Line(StdioChannel::Stdout, line) => lines.push(line),
+                    ProcessEvent::Data(_, _) => {
+                        panic!("spawn_async_lines should never emit ProcessEvent::Data")
+                    }
+                    ProcessEvent::Exit(_exit_status) => break 'outer,
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    assert_eq!(lines, vec!["one", "two", "three"]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_spawn_pty_echoes_written_input() {
+    use std::io::Write;
+
+    let mut process = Command::new("sh")
+        .spawn_pty((24, 80))
+        .expect("Could not spawn pty process");
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut events = Events::with_capacity(10);
+    let token = Token(1);
+    process
+        .register(&poll, token, Ready::all(), PollOpt::edge())
+        .expect("Could not register");
+
+    process
+        .write_all(b"echo hello_pty\n")
+        .expect("Could not write to pty master");
+    process.flush().expect("Could not flush pty master");
+
+    let mut buffer = String::new();
+    'outer: loop {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            assert_eq!(event.token(), token);
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                println!("{:?}", result);
+
+                match result {
+                    ProcessEvent::Data(StdioChannel::Stdout, data) => {
+                        buffer.push_str(&data);
+                        if buffer.contains("hello_pty") {
+                            break 'outer;
+                        }
+                    }
+                    ProcessEvent::Exit(_) => break 'outer,
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    assert!(
+        buffer.contains("hello_pty"),
+        "expected the shell's echo to come back through the pty, got: {:?}",
+        buffer
+    );
+
+    process.kill().expect("Could not kill process");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_stdio_pool_services_more_streams_than_worker_threads() {
+    // Spawn more processes (and so more stdout/stderr streams) than the pool has worker
+    // threads, to exercise several processes sharing one worker.
+    const PROCESS_COUNT: usize = 10;
+
+    let poll = Poll::new().expect("Could not spawn poll");
+    let mut processes: Vec<_> = (0..PROCESS_COUNT)
+        .map(|i| {
+            let process = Command::new("echo")
+                .arg(format!("worker-{}", i))
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn_async()
+                .expect("Could not spawn process");
+            let token = Token(i);
+            process
+                .register(&poll, token, Ready::all(), PollOpt::edge())
+                .expect("Could not register");
+            (token, process, false, String::new())
+        })
+        .collect();
+
+    let mut events = Events::with_capacity(32);
+    let mut remaining = PROCESS_COUNT;
+    while remaining > 0 {
+        poll.poll(&mut events, None).expect("Could not poll");
+        for event in &events {
+            let (_, process, exited, stdout) = processes
+                .iter_mut()
+                .find(|(token, _, _, _)| *token == event.token())
+                .expect("event for an unregistered process");
+            if *exited {
+                continue;
+            }
+            loop {
+                let result = match process.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("Could not receive from process"),
+                };
+                match result {
+                    ProcessEvent::Data(StdioChannel::Stdout, data) => stdout.push_str(&data),
+                    ProcessEvent::Exit(_) => {
+                        *exited = true;
+                        remaining -= 1;
+                        break;
+                    }
+                    ProcessEvent::IoError(_, _) | ProcessEvent::CommandError(_) => {
+                        assert!(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    for (i, _, _, stdout) in &processes {
+        let expected = format!("worker-{}", i.0);
+        assert!(
+            stdout.contains(&expected),
+            "expected stdout to contain {:?}, got: {:?}",
+            expected,
+            stdout
+        );
+    }
+}
+
 #[test]
 fn test_terminate() {
     let mut process = Command::new("ping");